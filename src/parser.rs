@@ -4,7 +4,9 @@
 use std::collections::{HashMap, HashSet};
 use std::collections::hash_map;
 use std::cell::RefCell;
+use std::cmp;
 use std::rc::Rc;
+use std::ptr;
 
 use syntax::abi;
 
@@ -73,11 +75,11 @@ fn decl_name(ctx: &mut ClangParserCtx, cursor: &Cursor) -> Global {
 
             let glob_decl = match cursor.kind() {
                 CXCursor_StructDecl => {
-                    let ci = Rc::new(RefCell::new(CompInfo::new(spelling, CompKind::Struct, vec!(), layout)));
+                    let ci = Rc::new(RefCell::new(CompInfo::new(spelling, CompKind::Struct, vec!(), record_layout(cursor, &ty))));
                     GCompDecl(ci)
                 }
                 CXCursor_UnionDecl => {
-                    let ci = Rc::new(RefCell::new(CompInfo::new(spelling, CompKind::Union, vec!(), layout)));
+                    let ci = Rc::new(RefCell::new(CompInfo::new(spelling, CompKind::Union, vec!(), record_layout(cursor, &ty))));
                     GCompDecl(ci)
                 }
                 CXCursor_EnumDecl => {
@@ -366,6 +368,7 @@ fn visit_composite(cursor: &Cursor, parent: &Cursor,
                     let mut ci_ = ci.borrow_mut();
                     visit_composite(c, p, ctx_, &mut ci_.members)
                 });
+                finalize_record_layout(&ci);
                 members.push(CompMember::Comp(decl.compinfo()));
             });
         }
@@ -394,6 +397,183 @@ fn visit_enum(cursor: &Cursor,
     return CXChildVisit_Continue;
 }
 
+// Clang doesn't expose `__attribute__((warn_unused_result))` through its own
+// cursor kind on most versions we support, but newer clangs surface it as a
+// child cursor of this kind. There's no named constant for it in `clang::ll`
+// yet, so we match on the raw value directly. (The attribute cursors start
+// at `CXCursor_FirstAttr = 400`; `WarnUnusedResultAttr` is 440, right after
+// `WarnUnusedAttr` at 439, and `AlignedAttr` is the next one, at 441.)
+static CXCursor_WarnUnusedResultAttr: int = 440;
+
+/// Tokenizes `cursor`'s source extent and returns each token's spelling and
+/// kind, skipping anything nested inside the outermost `{ ... }` body (a
+/// function or record definition's body). Attributes only ever appear in the
+/// declarator, before or after that body, so excluding it keeps an ordinary
+/// field or parameter named e.g. `packed` or `noreturn` from being mistaken
+/// for the attribute keyword of the same spelling.
+fn attribute_tokens(cursor: &Cursor) -> Vec<(String, Enum_CXTokenKind)> {
+    let tu = cursor.translation_unit();
+    let extent = cursor.extent();
+
+    let mut tokens: *mut CXToken = ptr::null_mut();
+    let mut num_tokens: uint = 0;
+    unsafe { clang_tokenize(tu, extent, &mut tokens, &mut num_tokens) };
+
+    let mut result = vec!();
+    let mut depth = 0i;
+    for i in range(0, num_tokens) {
+        let token = unsafe { *tokens.offset(i as int) };
+        let kind = clang_getTokenKind(token);
+        let spelling = unsafe { cx::cxstring_to_string(clang_getTokenSpelling(tu, token)) };
+
+        if spelling.as_slice() == "{" {
+            depth += 1;
+        } else if spelling.as_slice() == "}" {
+            depth -= 1;
+        } else if depth == 0 {
+            result.push((spelling, kind));
+        }
+    }
+
+    if !tokens.is_null() {
+        unsafe { clang_disposeTokens(tu, tokens, num_tokens) };
+    }
+
+    result
+}
+
+/// Looks for a token among `cursor`'s attribute tokens (see
+/// `attribute_tokens`) whose spelling is `name` and whose kind is `kind`.
+/// This is how we detect attributes such as
+/// `__attribute__((warn_unused_result))` and `_Noreturn` that libclang
+/// doesn't otherwise surface on `CXCursor_FunctionDecl`.
+fn has_attribute_token(cursor: &Cursor, name: &str, kind: Enum_CXTokenKind) -> bool {
+    attribute_tokens(cursor).iter().any(|&(ref spelling, tok_kind)| {
+        tok_kind == kind && spelling.as_slice() == name
+    })
+}
+
+/// Fallback for attributes hidden behind macros, where the token scan above
+/// won't see anything: some clang versions expose the attribute as a
+/// dedicated child cursor instead of (or as well as) a token.
+fn has_attribute_cursor(cursor: &Cursor, attr_kind: int) -> bool {
+    let mut found = false;
+    cursor.visit(|c, _| {
+        if c.kind() as int == attr_kind {
+            found = true;
+        }
+        CXChildVisit_Continue
+    });
+    found
+}
+
+fn has_must_use_attr(cursor: &Cursor) -> bool {
+    has_attribute_token(cursor, "warn_unused_result", CXToken_Identifier) ||
+        has_attribute_token(cursor, "__warn_unused_result__", CXToken_Identifier) ||
+        has_attribute_cursor(cursor, CXCursor_WarnUnusedResultAttr)
+}
+
+fn has_no_return_attr(cursor: &Cursor) -> bool {
+    has_attribute_token(cursor, "_Noreturn", CXToken_Keyword) ||
+        has_attribute_token(cursor, "noreturn", CXToken_Identifier) ||
+        // glibc and friends favor the reserved spelling precisely to avoid
+        // colliding with macros named `noreturn`.
+        has_attribute_token(cursor, "__noreturn__", CXToken_Identifier)
+}
+
+/// Scans `cursor`'s extent for `__attribute__((packed))` and
+/// `__attribute__((aligned(N)))`, returning `(packed, explicit_align)`.
+/// `aligned` without a following parenthesized integer literal just forces
+/// the type's natural alignment and isn't packing on its own, so it's only
+/// reported here when the literal can be parsed.
+fn scan_record_attrs(cursor: &Cursor) -> (bool, Option<uint>) {
+    let tokens = attribute_tokens(cursor);
+
+    let mut packed = false;
+    let mut align = None;
+
+    for (i, &(ref spelling, kind)) in tokens.iter().enumerate() {
+        if kind != CXToken_Identifier {
+            continue;
+        }
+
+        match spelling.as_slice() {
+            // The reserved `__x__` spellings are what system headers use to
+            // dodge macros named `packed`/`aligned`, same as `__noreturn__`
+            // above.
+            "packed" | "__packed__" => packed = true,
+            "aligned" | "__aligned__" if i + 2 < tokens.len() => {
+                let (ref paren, _) = tokens[i + 1];
+                let (ref lit, _) = tokens[i + 2];
+                if paren.as_slice() == "(" {
+                    align = from_str::<uint>(lit.as_slice());
+                }
+            }
+            _ => {}
+        }
+    }
+
+    (packed, align)
+}
+
+/// Builds the `Layout` for a struct/union decl, folding in any
+/// `packed`/`aligned(N)` attribute found on `cursor`.
+///
+/// `ty.align()`/`ty.size()` are always trusted for the effective layout:
+/// gcc/clang won't actually shrink a record's alignment below its widest
+/// member's natural alignment unless `packed` is also present, so a bare
+/// `aligned(N)` literal in the source is not necessarily what the compiler
+/// applied (e.g. `aligned(1)` on a struct containing an `int` still lays out
+/// at alignment 4). `explicit_align` is kept only as a hint for whether
+/// codegen should additionally emit `#[repr(align(N))]`.
+fn record_layout(cursor: &Cursor, ty: &cx::Type) -> Layout {
+    let (packed, explicit_align) = scan_record_attrs(cursor);
+    let mut layout = Layout::new(ty.size(), ty.align());
+    layout.packed = packed;
+    layout.requested_align = explicit_align;
+    layout
+}
+
+/// The natural (unpacked) alignment of `ty`, used to sanity-check a
+/// record's reported alignment against its largest member.
+fn natural_align(ty: &il::Type) -> uint {
+    match *ty {
+        TInt(_, layout) | TFloat(_, layout) | TPtr(_, _, layout) | TArray(box _, _, layout) => layout.align,
+        TComp(ref ci) => ci.borrow().layout.align,
+        TEnum(ref ei) => ei.borrow().layout.align,
+        TNamed(ref ti) => natural_align(&ti.borrow().ty),
+        TVoid | TFunc(..) => 1,
+    }
+}
+
+fn max_member_align(members: &[CompMember]) -> uint {
+    let mut max = 1u;
+    for member in members.iter() {
+        let align = match *member {
+            CompMember::Field(ref f) => natural_align(&f.ty),
+            CompMember::Comp(ref c) => c.borrow().layout.align,
+            CompMember::CompField(ref c, ref f) => cmp::max(c.borrow().layout.align, natural_align(&f.ty)),
+        };
+        max = cmp::max(max, align);
+    }
+    max
+}
+
+/// Once a record's members are known, double check the packing detected from
+/// attribute tokens against clang's reported alignment: if the real
+/// alignment is smaller than the natural alignment of the largest member,
+/// the record is packed even when no literal attribute token was found --
+/// this is how packing arriving via `#pragma pack` shows up.
+fn finalize_record_layout(ci: &Rc<RefCell<CompInfo>>) {
+    let mut ci = ci.borrow_mut();
+    if ci.layout.packed {
+        return;
+    }
+    if ci.layout.align < max_member_align(&ci.members) {
+        ci.layout.packed = true;
+    }
+}
+
 fn visit_top<'r>(cursor: &Cursor,
                  ctx: &mut ClangParserCtx) -> Enum_CXVisitorResult {
     if !match_pattern(ctx, cursor) {
@@ -409,6 +589,7 @@ fn visit_top<'r>(cursor: &Cursor,
                     let mut ci_ = ci.borrow_mut();
                     visit_composite(c, p, ctx_, &mut ci_.members)
                 });
+                finalize_record_layout(&ci);
                 ctx_.globals.push(GComp(ci));
             });
             return CXChildVisit_Continue;
@@ -444,6 +625,15 @@ fn visit_top<'r>(cursor: &Cursor,
             let vi = func.varinfo();
             let mut vi = vi.borrow_mut();
             vi.ty = TFunc(ret_ty.clone(), args_lst.clone(), ty.is_variadic(), abi);
+            // A function's declaration and its later definition are visited
+            // separately but share one `VarInfo` (looked up by canonical
+            // cursor in `decl_name`), and the attribute usually only appears
+            // on one of them -- e.g. a prototype carrying
+            // `warn_unused_result` followed by a plain definition without
+            // it. OR rather than overwrite so whichever redeclaration
+            // has the attribute wins instead of whichever is visited last.
+            vi.must_use = vi.must_use || has_must_use_attr(cursor);
+            vi.no_return = vi.no_return || has_no_return_attr(cursor);
             ctx.globals.push(func);
 
             return CXChildVisit_Continue;
@@ -553,3 +743,113 @@ pub fn parse(options: ClangParserOptions, logger: &Logger) -> Result<Vec<Global>
 
     Ok(ctx.globals)
 }
+
+#[cfg(test)]
+mod test {
+    use std::cell::RefCell;
+    use std::io::{File, TempDir};
+    use std::collections::HashSet;
+    use std::rc::Rc;
+
+    use super::{ClangParserOptions, parse};
+    use types::*;
+    use super::super::Logger;
+
+    struct TestLogger;
+
+    impl Logger for TestLogger {
+        fn error(&self, msg: &str) { panic!("clang error: {}", msg); }
+        fn warn(&self, _msg: &str) {}
+    }
+
+    fn parse_header(src: &str) -> Vec<Global> {
+        let dir = TempDir::new("bindgen-parser-test").unwrap();
+        let header = dir.path().join("test.h");
+        File::create(&header).write_str(src).unwrap();
+
+        let options = ClangParserOptions {
+            builtin_names: HashSet::new(),
+            builtins: false,
+            match_pat: vec!(),
+            emit_ast: false,
+            fail_on_bitfield: false,
+            fail_on_unknown_type: false,
+            override_enum_ty: None,
+            clang_args: vec!(header.as_str().unwrap().to_string()),
+        };
+
+        parse(options, &TestLogger).unwrap()
+    }
+
+    fn find_comp(globals: &[Global], name: &str) -> Rc<RefCell<CompInfo>> {
+        globals.iter().filter_map(|g| match *g {
+            GComp(ref ci) | GCompDecl(ref ci) if ci.borrow().name.as_slice() == name => Some(ci.clone()),
+            _ => None,
+        }).next().expect(format!("`{}` was not parsed", name).as_slice())
+    }
+
+    // Regression test for the `has_attribute_cursor` fallback's cursor-kind
+    // constant: macro-hidden `warn_unused_result` only shows up via the
+    // child-cursor check (the token scan can't see through the macro), so
+    // getting `CXCursor_WarnUnusedResultAttr` wrong silently breaks this case
+    // without breaking the token-based detection used everywhere else.
+    #[test]
+    fn detects_macro_hidden_warn_unused_result() {
+        let globals = parse_header("
+            #define WUR __attribute__((warn_unused_result))
+            WUR int must_check(void);
+        ");
+
+        let func = globals.iter().find(|g| match **g {
+            GFunc(ref vi) => vi.borrow().name.as_slice() == "must_check",
+            _ => false,
+        }).expect("`must_check` was not parsed");
+
+        match *func {
+            GFunc(ref vi) => assert!(vi.borrow().must_use),
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn detects_packed_attribute() {
+        let globals = parse_header("
+            struct S { char a; int b; } __attribute__((packed));
+        ");
+
+        assert!(find_comp(&globals, "S").borrow().layout.packed);
+    }
+
+    // `aligned(N)` alone (no `packed`) doesn't shrink a record's effective
+    // alignment below its widest member's natural alignment -- gcc/clang
+    // just ignore the literal in that case -- so the layout should stay
+    // unpacked at the natural alignment, with the literal only kept as a
+    // hint for `#[repr(align(N))]`.
+    #[test]
+    fn aligned_attribute_does_not_imply_packed() {
+        let globals = parse_header("
+            struct S { char a; int b; } __attribute__((aligned(1)));
+        ");
+
+        let ci = find_comp(&globals, "S");
+        let layout = ci.borrow().layout;
+        assert!(!layout.packed);
+        assert_eq!(layout.align, 4);
+        assert_eq!(layout.requested_align, Some(1));
+    }
+
+    // `#pragma pack` leaves no attribute token on the record cursor at all --
+    // it only shows up as a smaller-than-natural alignment reported by
+    // clang -- so this only passes via `finalize_record_layout`'s
+    // alignment-mismatch fallback, not the token scan.
+    #[test]
+    fn detects_pragma_pack_without_attribute_token() {
+        let globals = parse_header("
+            #pragma pack(1)
+            struct S { char a; int b; };
+            #pragma pack()
+        ");
+
+        assert!(find_comp(&globals, "S").borrow().layout.packed);
+    }
+}